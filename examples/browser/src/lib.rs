@@ -3,13 +3,13 @@ extern crate console_error_panic_hook;
 extern crate js_sys;
 extern crate web_sys;
 extern crate wasm_bindgen;
+extern crate wasm_bindgen_futures;
 
 use js_sys::{Array};
 use wasm_bindgen::prelude::*;
-use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::spawn_local;
 use web_sys::{console};
 
-use std::sync::{Arc, Mutex};
 use std::error::Error;
 
 use midir::{MidiInput, Ignore};
@@ -27,29 +27,23 @@ macro_rules! println {
 pub fn start() {
     std::panic::set_hook(Box::new(console_error_panic_hook::hook));
 
-    let token_outer = Arc::new(Mutex::new(None));
-    let token = token_outer.clone();
-    let closure : Closure<dyn FnMut()> = Closure::wrap(Box::new(move ||{
-        if run().unwrap() == true {
-            if let Some(token) = *token.lock().unwrap() {
-                web_sys::window().unwrap().clear_interval_with_handle(token);
-            }
+    spawn_local(async {
+        if let Err(err) = run().await {
+            println!("Error: {}", err);
         }
-    }));
-    *token_outer.lock().unwrap() = web_sys::window().unwrap().set_interval_with_callback_and_timeout_and_arguments_0(
-        closure.as_ref().unchecked_ref(),
-        200,
-    ).ok();
-    closure.forget();
+    });
 }
 
-fn run() -> Result<bool, Box<dyn Error>> {
-    let mut midi_in = MidiInput::new("midir reading input")?;
+async fn run() -> Result<(), Box<dyn Error>> {
+    let mut midi_in = MidiInput::new_async("midir reading input").await?;
     midi_in.ignore(Ignore::None);
 
     // Get an input port (read from console if multiple are available)
     let in_port = match midi_in.port_count() {
-        0 => return Ok(false),
+        0 => {
+            println!("No input port found");
+            return Ok(());
+        },
         1 => {
             println!("Choosing the only available input port: {}", midi_in.port_name(0).unwrap());
             0
@@ -74,5 +68,5 @@ fn run() -> Result<bool, Box<dyn Error>> {
 
     println!("Connection open, reading input from '{}'", in_port_name);
     Box::leak(Box::new(_conn_in));
-    Ok(true)
+    Ok(())
 }
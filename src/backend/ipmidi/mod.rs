@@ -0,0 +1,485 @@
+//! IP MIDI backend.
+//!
+//! Transports MIDI messages over IP multicast UDP, in the style of
+//! Ardour's `IPMIDIPort` and the ipMIDI driver it interoperates with.
+//! Ports in this backend are not physical devices: each port is a
+//! `(multicast group, UDP port)` endpoint, and any two processes on the
+//! network (midir-based or not) that join the same endpoint can exchange
+//! MIDI messages without any hardware interface or driver.
+//!
+//! Reference:
+//! * [ipMIDI](http://www.nerds.de/en/ipmidi.html)
+
+extern crate socket2;
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use self::socket2::{Domain, Socket, Type};
+
+use ::errors::*;
+use ::Ignore;
+
+/// The multicast group ipMIDI-compatible tools default to.
+const DEFAULT_GROUP: Ipv4Addr = Ipv4Addr::new(225, 0, 0, 37);
+
+/// The first UDP port of the default endpoint range; ipMIDI itself
+/// exposes 16 consecutive ports starting here, one per virtual MIDI port.
+const DEFAULT_BASE_PORT: u16 = 21928;
+
+/// The number of endpoints exposed by default when no explicit endpoint
+/// list is supplied.
+const DEFAULT_PORT_COUNT: u16 = 16;
+
+fn default_endpoints() -> Vec<SocketAddr> {
+    (0..DEFAULT_PORT_COUNT)
+        .map(|i| SocketAddr::new(IpAddr::V4(DEFAULT_GROUP), DEFAULT_BASE_PORT + i))
+        .collect()
+}
+
+/// Binds a UDP socket for receiving `addr`'s multicast group, with
+/// `SO_REUSEADDR`/`SO_REUSEPORT` set so multiple processes (or multiple
+/// `connect()`s within the same process) can all bind the same endpoint,
+/// as ipMIDI-compatible tools expect.
+fn bind_multicast(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port());
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&bind_addr.into())?;
+    let socket: UdpSocket = socket.into();
+    if let IpAddr::V4(group) = addr.ip() {
+        socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+    }
+    Ok(socket)
+}
+
+/// Splits a datagram containing one or more (possibly running-status
+/// compressed) MIDI messages into complete messages, each with an
+/// explicit leading status byte. `running_status` persists the last seen
+/// status byte across datagrams from the same endpoint.
+fn expand_running_status(buf: &[u8], running_status: &mut u8) -> Vec<Vec<u8>> {
+    fn data_len(status: u8) -> Option<usize> {
+        match status {
+            0x80..=0xBF | 0xE0..=0xEF => Some(2),
+            0xC0..=0xDF => Some(1),
+            0xF1 | 0xF3 => Some(1),
+            0xF2 => Some(2),
+            0xF6 | 0xF8..=0xFF => Some(0),
+            _ => None,
+        }
+    }
+
+    let mut messages = Vec::new();
+    let mut i = 0;
+    while i < buf.len() {
+        let status = if buf[i] & 0x80 != 0 {
+            i += 1;
+            buf[i - 1]
+        } else {
+            *running_status
+        };
+        if status & 0x80 == 0 {
+            break; // garbage with no status byte to run with; drop the rest of the datagram
+        }
+        if status < 0xF0 {
+            *running_status = status;
+        }
+        let len = match data_len(status) {
+            Some(len) => len,
+            None => break, // sysex or unsupported status; not handled by this minimal parser
+        };
+        if i + len > buf.len() { break; }
+        let mut message = Vec::with_capacity(1 + len);
+        message.push(status);
+        message.extend_from_slice(&buf[i..i + len]);
+        messages.push(message);
+        i += len;
+    }
+    messages
+}
+
+fn now_micros(epoch: Instant) -> u64 {
+    Instant::now().duration_since(epoch).as_micros() as u64
+}
+
+/// A single process-wide reference point shared by every `MidiInputConnection`
+/// and `MidiOutputConnection` opened by this backend, so a timestamp read
+/// from one connection's input callback can be handed to any connection's
+/// `send_at`, per `MidiOutputConnection::send_at`'s epoch requirement.
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MidiInputPort {
+    addr: SocketAddr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MidiOutputPort {
+    addr: SocketAddr,
+}
+
+pub struct MidiInput {
+    ignore_flags: Ignore,
+    endpoints: Vec<SocketAddr>,
+}
+
+impl MidiInput {
+    pub fn new(_client_name: &str) -> Result<Self, InitError> {
+        Ok(MidiInput { ignore_flags: Ignore::None, endpoints: default_endpoints() })
+    }
+
+    /// There's no OS-level permission prompt to skip on this backend, and
+    /// endpoints aren't split into hardware/software, so
+    /// `include_software_synths` has no effect; `request_sysex: false` is
+    /// honored by filtering incoming sysex messages via `Ignore::Sysex`.
+    pub fn new_with_options(_client_name: &str, options: ::MidiAccessOptions) -> Result<Self, InitError> {
+        let ignore_flags = if options.request_sysex { Ignore::None } else { Ignore::Sysex };
+        Ok(MidiInput { ignore_flags, endpoints: default_endpoints() })
+    }
+
+    /// Like `new`, but joins `endpoints` instead of the default
+    /// `225.0.0.37:21928..+16` range; each endpoint becomes one port.
+    pub fn new_with_endpoints(client_name: &str, endpoints: Vec<SocketAddr>) -> Result<Self, InitError> {
+        let mut input = Self::new(client_name)?;
+        input.endpoints = endpoints;
+        Ok(input)
+    }
+
+    pub fn ignore(&mut self, flags: Ignore) {
+        self.ignore_flags = flags;
+    }
+
+    pub fn ports_internal(&self) -> Vec<::MidiInputPort> {
+        self.endpoints.iter()
+            .map(|&addr| ::MidiInputPort { imp: MidiInputPort { addr } })
+            .collect()
+    }
+
+    pub fn port_count(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    /// The set of endpoints is fixed at construction time, so this backend
+    /// never has anything to report; the returned guard is a no-op.
+    pub fn set_port_watcher(&mut self, _callback: Box<dyn FnMut(::PortEvent) + Send>) -> PortWatcher {
+        PortWatcher {}
+    }
+
+    pub fn port_name(&self, port: &MidiInputPort) -> Result<String, PortInfoError> {
+        Ok(format!("{}:{}", port.addr.ip(), port.addr.port()))
+    }
+
+    /// Configured endpoints never disappear on their own, so this is
+    /// always `Connected`.
+    pub fn port_state(&self, _port: &MidiInputPort) -> ::PortState {
+        ::PortState::Connected
+    }
+
+    /// This backend does not currently track which of its configured
+    /// endpoints are open, so this is always `Closed`.
+    pub fn port_connection_state(&self, _port: &MidiInputPort) -> ::PortConnectionState {
+        ::PortConnectionState::Closed
+    }
+
+    pub fn connect<F, T: Send + 'static>(
+        self, port: &MidiInputPort, _port_name: &str, mut callback: F, data: T
+    ) -> Result<MidiInputConnection<T>, ConnectError<MidiInput>>
+        where F: FnMut(u64, &[u8], &mut T) + Send + 'static
+    {
+        let socket = match bind_multicast(port.addr) {
+            Ok(socket) => socket,
+            Err(_) => return Err(ConnectError::new(ConnectErrorKind::Other("failed to join multicast group"), self)),
+        };
+        socket.set_read_timeout(Some(Duration::from_millis(100))).ok();
+
+        let ignore_flags = self.ignore_flags;
+        let endpoints = self.endpoints.clone();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let user_data = Arc::new(Mutex::new(Some(data)));
+        let thread_data = user_data.clone();
+        let epoch = epoch();
+
+        let handle = thread::Builder::new()
+            .name("midir ipmidi input".into())
+            .spawn(move || {
+                let mut running_status = 0u8;
+                let mut buf = [0u8; 1024];
+                while thread_running.load(Ordering::Acquire) {
+                    let len = match socket.recv(&mut buf) {
+                        Ok(len) => len,
+                        Err(_) => continue, // read timeout, or transient error; re-check thread_running
+                    };
+                    let timestamp = now_micros(epoch);
+                    for message in expand_running_status(&buf[..len], &mut running_status) {
+                        let status = message[0];
+                        if !(status == 0xF0 && ignore_flags.contains(Ignore::Sysex) ||
+                             status == 0xF1 && ignore_flags.contains(Ignore::Time) ||
+                             status == 0xF8 && ignore_flags.contains(Ignore::Time) ||
+                             status == 0xFE && ignore_flags.contains(Ignore::ActiveSense))
+                        {
+                            let mut data = thread_data.lock().unwrap();
+                            callback(timestamp, &message, data.as_mut().unwrap());
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn ipmidi receive thread");
+
+        Ok(MidiInputConnection { ignore_flags, endpoints, running, handle: Some(handle), user_data })
+    }
+}
+
+pub struct MidiInputConnection<T> {
+    ignore_flags: Ignore,
+    endpoints:    Vec<SocketAddr>,
+    running:      Arc<AtomicBool>,
+    handle:       Option<JoinHandle<()>>,
+    user_data:    Arc<Mutex<Option<T>>>,
+}
+
+impl<T> MidiInputConnection<T> {
+    pub fn close(mut self) -> (MidiInput, T) {
+        self.running.store(false, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        let data = self.user_data.lock().unwrap().take().unwrap();
+        (MidiInput { ignore_flags: self.ignore_flags, endpoints: self.endpoints }, data)
+    }
+}
+
+/// A message scheduled to be sent once `at_us` (in the output connection's
+/// timestamp epoch) has passed. Ordered so a `BinaryHeap<DueMessage>` pops
+/// the earliest-due message first.
+struct DueMessage {
+    at_us:   u64,
+    message: Vec<u8>,
+}
+
+impl PartialEq for DueMessage {
+    fn eq(&self, other: &Self) -> bool { self.at_us == other.at_us }
+}
+impl Eq for DueMessage {}
+impl PartialOrd for DueMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> { Some(self.cmp(other)) }
+}
+impl Ord for DueMessage {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.at_us.cmp(&self.at_us) // reversed, so the heap is a min-heap on `at_us`
+    }
+}
+
+/// Runs on its own thread for the lifetime of a `MidiOutputConnection`,
+/// flushing scheduled messages as they come due. `send` (send-now) bypasses
+/// this entirely and writes straight to the socket.
+fn spawn_scheduler(socket: UdpSocket, addr: SocketAddr, epoch: Instant) -> (Sender<DueMessage>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel::<DueMessage>();
+    let handle = thread::Builder::new()
+        .name("midir ipmidi output scheduler".into())
+        .spawn(move || {
+            let mut pending: BinaryHeap<DueMessage> = BinaryHeap::new();
+            loop {
+                let timeout = match pending.peek() {
+                    Some(due) => {
+                        let now = now_micros(epoch);
+                        if due.at_us <= now { Duration::from_micros(0) } else { Duration::from_micros(due.at_us - now) }
+                    },
+                    None => Duration::from_secs(3600), // idle; woken immediately once a message arrives or the connection closes
+                };
+                let disconnected = match rx.recv_timeout(timeout) {
+                    Ok(due) => { pending.push(due); false },
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => true,
+                };
+                let now = now_micros(epoch);
+                while pending.peek().map_or(false, |due| due.at_us <= now) {
+                    let due = pending.pop().unwrap();
+                    let _ = socket.send_to(&due.message, addr);
+                }
+                if disconnected {
+                    // The connection is closing: flush whatever's left
+                    // instead of silently dropping messages that hadn't
+                    // come due yet.
+                    while let Some(due) = pending.pop() {
+                        let _ = socket.send_to(&due.message, addr);
+                    }
+                    break;
+                }
+            }
+        })
+        .expect("failed to spawn ipmidi output scheduler thread");
+    (tx, handle)
+}
+
+pub struct MidiOutput {
+    endpoints: Vec<SocketAddr>,
+}
+
+impl MidiOutput {
+    pub fn new(_client_name: &str) -> Result<Self, InitError> {
+        Ok(MidiOutput { endpoints: default_endpoints() })
+    }
+
+    /// This backend has no OS-level permission prompt and no way to
+    /// suppress outgoing sysex messages a caller explicitly sends, so
+    /// `options` has no effect; it exists for parity with `MidiInput`.
+    pub fn new_with_options(_client_name: &str, _options: ::MidiAccessOptions) -> Result<Self, InitError> {
+        Ok(MidiOutput { endpoints: default_endpoints() })
+    }
+
+    /// Like `new`, but joins `endpoints` instead of the default
+    /// `225.0.0.37:21928..+16` range; each endpoint becomes one port.
+    pub fn new_with_endpoints(client_name: &str, endpoints: Vec<SocketAddr>) -> Result<Self, InitError> {
+        let mut output = Self::new(client_name)?;
+        output.endpoints = endpoints;
+        Ok(output)
+    }
+
+    pub fn ports_internal(&self) -> Vec<::MidiOutputPort> {
+        self.endpoints.iter()
+            .map(|&addr| ::MidiOutputPort { imp: MidiOutputPort { addr } })
+            .collect()
+    }
+
+    pub fn port_count(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    /// The set of endpoints is fixed at construction time, so this backend
+    /// never has anything to report; the returned guard is a no-op.
+    pub fn set_port_watcher(&mut self, _callback: Box<dyn FnMut(::PortEvent) + Send>) -> PortWatcher {
+        PortWatcher {}
+    }
+
+    pub fn port_name(&self, port: &MidiOutputPort) -> Result<String, PortInfoError> {
+        Ok(format!("{}:{}", port.addr.ip(), port.addr.port()))
+    }
+
+    /// Configured endpoints never disappear on their own, so this is
+    /// always `Connected`.
+    pub fn port_state(&self, _port: &MidiOutputPort) -> ::PortState {
+        ::PortState::Connected
+    }
+
+    /// This backend does not currently track which of its configured
+    /// endpoints are open, so this is always `Closed`.
+    pub fn port_connection_state(&self, _port: &MidiOutputPort) -> ::PortConnectionState {
+        ::PortConnectionState::Closed
+    }
+
+    pub fn connect(self, port: &MidiOutputPort, _port_name: &str) -> Result<MidiOutputConnection, ConnectError<MidiOutput>> {
+        let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+            Ok(socket) => socket,
+            Err(_) => return Err(ConnectError::new(ConnectErrorKind::Other("failed to open multicast socket"), self)),
+        };
+        let scheduler_socket = match socket.try_clone() {
+            Ok(socket) => socket,
+            Err(_) => return Err(ConnectError::new(ConnectErrorKind::Other("failed to open multicast socket"), self)),
+        };
+        let endpoints = self.endpoints.clone();
+        let epoch = epoch();
+        let (scheduler, scheduler_handle) = spawn_scheduler(scheduler_socket, port.addr, epoch);
+        Ok(MidiOutputConnection { socket, addr: port.addr, endpoints, scheduler, scheduler_handle: Some(scheduler_handle), epoch })
+    }
+}
+
+pub struct MidiOutputConnection {
+    socket:           UdpSocket,
+    addr:             SocketAddr,
+    endpoints:        Vec<SocketAddr>,
+    scheduler:        Sender<DueMessage>,
+    scheduler_handle: Option<JoinHandle<()>>,
+    epoch:            Instant,
+}
+
+impl MidiOutputConnection {
+    /// Drops `scheduler`, which signals its thread to send every message
+    /// still pending at once (including ones not yet due) and exit, then
+    /// joins it before returning, so
+    /// no scheduled `send_at` message is silently lost on close.
+    pub fn close(mut self) -> MidiOutput {
+        drop(self.scheduler);
+        if let Some(handle) = self.scheduler_handle.take() {
+            let _ = handle.join();
+        }
+        MidiOutput { endpoints: self.endpoints }
+    }
+
+    pub fn send(&mut self, message: &[u8]) -> Result<(), SendError> {
+        self.socket.send_to(message, self.addr)
+            .map(|_| ())
+            .map_err(|_| SendError::Other("failed to send multicast datagram"))
+    }
+
+    pub fn send_at(&mut self, message: &[u8], timestamp_us: u64) -> Result<(), SendError> {
+        if timestamp_us <= now_micros(self.epoch) {
+            return self.send(message);
+        }
+        self.scheduler.send(DueMessage { at_us: timestamp_us, message: message.to_vec() })
+            .map_err(|_| SendError::Other("output scheduler thread has stopped"))
+    }
+}
+
+/// No-op hot-plug subscription guard; see `MidiInput::set_port_watcher` /
+/// `MidiOutput::set_port_watcher` on this backend.
+pub struct PortWatcher {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_running_status_carries_over_across_calls() {
+        let mut running_status = 0u8;
+        let messages = expand_running_status(&[0x90, 0x40, 0x7f], &mut running_status);
+        assert_eq!(messages, vec![vec![0x90, 0x40, 0x7f]]);
+        assert_eq!(running_status, 0x90);
+
+        // Next datagram omits the status byte, relying on running status.
+        let messages = expand_running_status(&[0x41, 0x00], &mut running_status);
+        assert_eq!(messages, vec![vec![0x90, 0x41, 0x00]]);
+    }
+
+    #[test]
+    fn expand_running_status_drops_truncated_datagram() {
+        let mut running_status = 0u8;
+        // Note on needs 2 data bytes, but only 1 is present.
+        let messages = expand_running_status(&[0x90, 0x40], &mut running_status);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn expand_running_status_stops_at_unsupported_status() {
+        let mut running_status = 0u8;
+        // Sysex (0xF0) isn't handled by this minimal parser; the leading
+        // note-on should still be parsed before parsing stops.
+        let messages = expand_running_status(&[0x90, 0x40, 0x7f, 0xf0, 0x01, 0x02], &mut running_status);
+        assert_eq!(messages, vec![vec![0x90, 0x40, 0x7f]]);
+    }
+
+    #[test]
+    fn due_message_min_heap_pops_earliest_first() {
+        let mut heap = BinaryHeap::new();
+        heap.push(DueMessage { at_us: 300, message: vec![3] });
+        heap.push(DueMessage { at_us: 100, message: vec![1] });
+        heap.push(DueMessage { at_us: 200, message: vec![2] });
+
+        assert_eq!(heap.pop().unwrap().at_us, 100);
+        assert_eq!(heap.pop().unwrap().at_us, 200);
+        assert_eq!(heap.pop().unwrap().at_us, 300);
+        assert!(heap.pop().is_none());
+    }
+}
@@ -6,20 +6,23 @@
 
 extern crate js_sys;
 extern crate wasm_bindgen;
+extern crate wasm_bindgen_futures;
 extern crate web_sys;
 
 use self::js_sys::{Map, Promise, Uint8Array};
 use self::wasm_bindgen::prelude::*;
 use self::wasm_bindgen::JsCast;
-use self::web_sys::{MidiAccess, MidiOptions, MidiPort, MidiMessageEvent};
+use self::wasm_bindgen_futures::JsFuture;
+use self::web_sys::{MidiAccess, MidiConnectionEvent, MidiOptions, MidiPort, MidiPortConnectionState, MidiPortDeviceState, MidiMessageEvent};
 
 use std::cell::RefCell;
 use std::collections::hash_map::*;
+use std::mem;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 
 use ::errors::*;
-use ::Ignore;
+use ::{Ignore, PortEvent, PortEventKind, PortConnectionState, PortState};
 
 
 
@@ -65,33 +68,61 @@ impl<T: Deref<Target = MidiPort> + JsCast> DeviceSet<T> {
 
 
 
+fn port_state(device: &MidiPort) -> PortState {
+    match device.state() {
+        MidiPortDeviceState::Connected => PortState::Connected,
+        _ => PortState::Disconnected,
+    }
+}
+
+fn port_connection_state(device: &MidiPort) -> PortConnectionState {
+    match device.connection() {
+        MidiPortConnectionState::Open => PortConnectionState::Open,
+        MidiPortConnectionState::Pending => PortConnectionState::Pending,
+        _ => PortConnectionState::Closed,
+    }
+}
+
 thread_local! {
     static STATIC : RefCell<Static> = RefCell::new(Static::new());
 }
 
+/// Rejection message used by `request_midi_access_promise` when there's no
+/// `window` to request access from, or the browser otherwise lacks Web MIDI
+/// support, so `new_async`/`new_async_with_options` can recognize it below.
+const UNSUPPORTED_MESSAGE: &str = "Web MIDI is not supported in this environment";
+
 struct Static {
     pub access:         Option<MidiAccess>,
     pub request:        Option<Promise>,
     pub ever_requested: bool,
+    pub unsupported:    bool,
 
     pub on_ok:          Closure<dyn FnMut(JsValue)>,
     pub on_err:         Closure<dyn FnMut(JsValue)>,
+    pub on_statechange: Closure<dyn FnMut(MidiConnectionEvent)>,
 
     pub input_set:      DeviceSet<web_sys::MidiInput>,
     pub output_set:     DeviceSet<web_sys::MidiOutput>,
+
+    pub next_watcher_id:  u64,
+    pub input_watchers:   HashMap<u64, Box<dyn FnMut(PortEvent)>>,
+    pub output_watchers:  HashMap<u64, Box<dyn FnMut(PortEvent)>>,
 }
 
 impl Static {
     pub fn new() -> Self {
-        let mut s = Self {
+        Self {
             access:         None,
             request:        None,
             ever_requested: false,
+            unsupported:    false,
 
             on_ok: Closure::wrap(Box::new(|access| {
                 STATIC.with(|s|{
                     let mut s = s.borrow_mut();
                     let access : MidiAccess = access.dyn_into().unwrap();
+                    access.set_onstatechange(Some(s.on_statechange.as_ref().unchecked_ref()));
                     s.request = None;
                     s.access = Some(access);
                 });
@@ -102,20 +133,36 @@ impl Static {
                     s.request = None;
                 });
             })),
+            on_statechange: Closure::wrap(Box::new(|event: MidiConnectionEvent| {
+                let port = if let Some(p) = event.port() { p } else { return; };
+                let kind = match port.state() {
+                    MidiPortDeviceState::Connected => PortEventKind::Added,
+                    MidiPortDeviceState::Disconnected => PortEventKind::Removed,
+                    _ => return,
+                };
+                let port_event = PortEvent { id: port.id(), name: port.name(), kind };
+
+                let watcher_kind = STATIC.with(|s| s.borrow_mut().handle_statechange(&port));
+                let watcher_kind = if let Some(k) = watcher_kind { k } else { return; };
+
+                // Drain the watcher callbacks into a local map before invoking them:
+                // a callback might re-enter STATIC (e.g. to call `ports()`, or by
+                // dropping a `PortWatcher`), which would otherwise panic with
+                // `BorrowMutError` against the mutable borrow held here.
+                let mut watchers = STATIC.with(|s| s.borrow_mut().take_watchers(watcher_kind));
+                for callback in watchers.values_mut() {
+                    callback(port_event.clone());
+                }
+                STATIC.with(|s| s.borrow_mut().restore_watchers(watcher_kind, watchers));
+            })),
 
             input_set: DeviceSet::new(),
             output_set: DeviceSet::new(),
-        };
-        // Some notes on sysex behavior:
-        //  1) Some devices (but not all!) may work without sysex
-        //  2) Chrome will only prompt the end user to grant permission if they requested sysex permissions for now...
-        //      but that's changing soon for "security reasons" (reduced fingerprinting? poorly tested drivers?):
-        //      https://www.chromestatus.com/feature/5138066234671104
-        //
-        //  I've chosen to hardcode sysex=true here, since that'll be compatible with more devices, *and* should change
-        //  less behavior when Chrome's changes land.
-        s.request_midi_access(true);
-        s
+
+            next_watcher_id: 0,
+            input_watchers:  HashMap::new(),
+            output_watchers: HashMap::new(),
+        }
     }
 
     pub fn refresh_inputs(&mut self) {
@@ -130,17 +177,108 @@ impl Static {
         self.output_set.found_map(&outputs.unchecked_into());
     }
 
-    fn request_midi_access(&mut self, sysex: bool) {
+    fn request_midi_access(&mut self, sysex: bool, software: bool) {
         self.ever_requested = true;
         if self.access.is_some() { return; } // Already have access
         if self.request.is_some() { return; } // Mid-request already
-        let window = if let Some(w) = web_sys::window() { w } else { return; };
+        let window = if let Some(w) = web_sys::window() { w } else { self.unsupported = true; return; };
 
-        let _request = match window.navigator().request_midi_access_with_options(MidiOptions::new().sysex(sysex)) {
+        let _request = match window.navigator().request_midi_access_with_options(MidiOptions::new().sysex(sysex).software(software)) {
             Ok(p) => { self.request = Some(p.then2(&self.on_ok, &self.on_err)); },
-            Err(_) => { return; } // node.js? brower doesn't support webmidi? other?
+            Err(_) => { self.unsupported = true; return; } // node.js? brower doesn't support webmidi? other?
         };
     }
+
+    /// Like `request_midi_access`, but returns a promise that settles once
+    /// access has been granted (or denied, or found to be unsupported),
+    /// for `new_async` to await. The returned promise is rejected if there's
+    /// no `window` to request access from, or the browser otherwise lacks
+    /// Web MIDI support, so callers can distinguish that from "already
+    /// granted" instead of silently treating both as success.
+    fn request_midi_access_promise(&mut self, sysex: bool, software: bool) -> Promise {
+        self.request_midi_access(sysex, software);
+        if self.unsupported {
+            return Promise::reject(&JsValue::from_str(UNSUPPORTED_MESSAGE));
+        }
+        match self.request.as_ref() {
+            Some(request) => request.clone(),
+            None => Promise::resolve(&JsValue::UNDEFINED), // access already granted
+        }
+    }
+
+    /// Updates the relevant `DeviceSet` for `port` and reports which set of
+    /// watcher callbacks (if any) should be notified; does not invoke them
+    /// itself so callers can do so outside of a `STATIC` borrow.
+    fn handle_statechange(&mut self, port: &MidiPort) -> Option<PortWatcherKind> {
+        if let Ok(input) = port.clone().dyn_into::<web_sys::MidiInput>() {
+            self.input_set.found_one(input);
+            Some(PortWatcherKind::Input)
+        } else if let Ok(output) = port.clone().dyn_into::<web_sys::MidiOutput>() {
+            self.output_set.found_one(output);
+            Some(PortWatcherKind::Output)
+        } else {
+            None
+        }
+    }
+
+    /// Removes and returns the watcher callbacks for `kind`, leaving an
+    /// empty map in their place. Pair with `restore_watchers` once the
+    /// callbacks have been invoked.
+    fn take_watchers(&mut self, kind: PortWatcherKind) -> HashMap<u64, Box<dyn FnMut(PortEvent)>> {
+        let watchers = match kind {
+            PortWatcherKind::Input => &mut self.input_watchers,
+            PortWatcherKind::Output => &mut self.output_watchers,
+        };
+        mem::replace(watchers, HashMap::new())
+    }
+
+    /// Merges `watchers` back in after `take_watchers`, preserving any
+    /// callback registered (or removed) while they were out on loan.
+    fn restore_watchers(&mut self, kind: PortWatcherKind, watchers: HashMap<u64, Box<dyn FnMut(PortEvent)>>) {
+        let target = match kind {
+            PortWatcherKind::Input => &mut self.input_watchers,
+            PortWatcherKind::Output => &mut self.output_watchers,
+        };
+        target.extend(watchers);
+    }
+
+    fn add_input_watcher(&mut self, callback: Box<dyn FnMut(PortEvent)>) -> u64 {
+        self.refresh_inputs();
+        let id = self.next_watcher_id;
+        self.next_watcher_id += 1;
+        self.input_watchers.insert(id, callback);
+        id
+    }
+
+    fn add_output_watcher(&mut self, callback: Box<dyn FnMut(PortEvent)>) -> u64 {
+        self.refresh_outputs();
+        let id = self.next_watcher_id;
+        self.next_watcher_id += 1;
+        self.output_watchers.insert(id, callback);
+        id
+    }
+}
+
+/// A hot-plug notification subscription created by `MidiInput::set_port_watcher`
+/// or `MidiOutput::set_port_watcher`. Dropping it unregisters the callback.
+pub struct PortWatcher {
+    id:   u64,
+    kind: PortWatcherKind,
+}
+
+#[derive(Clone, Copy)]
+enum PortWatcherKind { Input, Output }
+
+impl Drop for PortWatcher {
+    fn drop(&mut self) {
+        STATIC.with(|s| {
+            let mut s = s.borrow_mut();
+            match self.kind {
+                PortWatcherKind::Input => { s.input_watchers.remove(&self.id); },
+                PortWatcherKind::Output => { s.output_watchers.remove(&self.id); },
+            }
+        });
+    }
 }
 
 pub struct MidiInput {
@@ -149,9 +287,41 @@ pub struct MidiInput {
 
 impl MidiInput {
     pub fn new(_client_name: &str) -> Result<Self, InitError> {
+        // Sysex is requested by default: some devices don't work without it, and Chrome currently
+        // only prompts the user to grant MIDI access at all if sysex permission is requested too.
+        STATIC.with(|s| s.borrow_mut().request_midi_access(true, false));
         Ok(MidiInput { ignore_flags: Ignore::None })
     }
 
+    /// Like `new`, but with explicit control over whether sysex permission
+    /// and software synths are requested; see `MidiAccessOptions`.
+    pub fn new_with_options(_client_name: &str, options: ::MidiAccessOptions) -> Result<Self, InitError> {
+        STATIC.with(|s| s.borrow_mut().request_midi_access(options.request_sysex, options.include_software_synths));
+        Ok(MidiInput { ignore_flags: Ignore::None })
+    }
+
+    /// Like `new`, but resolves only once the browser has settled the
+    /// `requestMIDIAccess` permission prompt, so `ports()`/`port_count()`
+    /// immediately reflect reality instead of racing the promise.
+    pub async fn new_async(client_name: &str) -> Result<Self, InitError> {
+        Self::new_async_with_options(client_name, ::MidiAccessOptions::default()).await
+    }
+
+    /// Combines `new_async` and `new_with_options`: resolves only once the
+    /// browser has settled the `requestMIDIAccess` permission prompt, using
+    /// the sysex/software-synth settings from `options`.
+    pub async fn new_async_with_options(client_name: &str, options: ::MidiAccessOptions) -> Result<Self, InitError> {
+        let promise = STATIC.with(|s| s.borrow_mut().request_midi_access_promise(options.request_sysex, options.include_software_synths));
+        JsFuture::from(promise).await.map_err(|err| match err.as_string() {
+            Some(ref msg) if msg.as_str() == UNSUPPORTED_MESSAGE => InitError::Other(UNSUPPORTED_MESSAGE),
+            _ => InitError::Other("requestMIDIAccess was rejected"),
+        })?;
+        if STATIC.with(|s| s.borrow().access.is_none()) {
+            return Err(InitError::Other("requestMIDIAccess was rejected"));
+        }
+        Self::new_with_options(client_name, options)
+    }
+
     pub fn ignore(&mut self, flags: Ignore) {
         self.ignore_flags = flags;
     }
@@ -164,6 +334,11 @@ impl MidiInput {
         })
     }
 
+    pub fn set_port_watcher(&mut self, callback: Box<dyn FnMut(PortEvent) + Send>) -> PortWatcher {
+        let id = STATIC.with(|s| s.borrow_mut().add_input_watcher(callback));
+        PortWatcher { id, kind: PortWatcherKind::Input }
+    }
+
     pub fn port_name(&self, port_number: usize) -> Result<String, PortInfoError> {
         STATIC.with(|s| {
             let s = s.borrow_mut();
@@ -173,6 +348,22 @@ impl MidiInput {
         })
     }
 
+    pub fn port_state(&self, port_number: usize) -> PortState {
+        STATIC.with(|s| {
+            let s = s.borrow_mut();
+            if port_number >= s.input_set.len() { return PortState::Disconnected; }
+            port_state(&s.input_set.list()[port_number])
+        })
+    }
+
+    pub fn port_connection_state(&self, port_number: usize) -> PortConnectionState {
+        STATIC.with(|s| {
+            let s = s.borrow_mut();
+            if port_number >= s.input_set.len() { return PortConnectionState::Closed; }
+            port_connection_state(&s.input_set.list()[port_number])
+        })
+    }
+
     pub fn connect<F, T: Send + 'static>(
         self, port_number: usize, _port_name: &str, mut callback: F, data: T
     ) -> Result<MidiInputConnection<T>, ConnectError<MidiInput>>
@@ -241,9 +432,41 @@ pub struct MidiOutput {
 
 impl MidiOutput {
     pub fn new(_client_name: &str) -> Result<Self, InitError> {
+        // Sysex is requested by default: some devices don't work without it, and Chrome currently
+        // only prompts the user to grant MIDI access at all if sysex permission is requested too.
+        STATIC.with(|s| s.borrow_mut().request_midi_access(true, false));
+        Ok(MidiOutput {})
+    }
+
+    /// Like `new`, but with explicit control over whether sysex permission
+    /// and software synths are requested; see `MidiAccessOptions`.
+    pub fn new_with_options(_client_name: &str, options: ::MidiAccessOptions) -> Result<Self, InitError> {
+        STATIC.with(|s| s.borrow_mut().request_midi_access(options.request_sysex, options.include_software_synths));
         Ok(MidiOutput {})
     }
 
+    /// Like `new`, but resolves only once the browser has settled the
+    /// `requestMIDIAccess` permission prompt, so `ports()`/`port_count()`
+    /// immediately reflect reality instead of racing the promise.
+    pub async fn new_async(client_name: &str) -> Result<Self, InitError> {
+        Self::new_async_with_options(client_name, ::MidiAccessOptions::default()).await
+    }
+
+    /// Combines `new_async` and `new_with_options`: resolves only once the
+    /// browser has settled the `requestMIDIAccess` permission prompt, using
+    /// the sysex/software-synth settings from `options`.
+    pub async fn new_async_with_options(client_name: &str, options: ::MidiAccessOptions) -> Result<Self, InitError> {
+        let promise = STATIC.with(|s| s.borrow_mut().request_midi_access_promise(options.request_sysex, options.include_software_synths));
+        JsFuture::from(promise).await.map_err(|err| match err.as_string() {
+            Some(ref msg) if msg.as_str() == UNSUPPORTED_MESSAGE => InitError::Other(UNSUPPORTED_MESSAGE),
+            _ => InitError::Other("requestMIDIAccess was rejected"),
+        })?;
+        if STATIC.with(|s| s.borrow().access.is_none()) {
+            return Err(InitError::Other("requestMIDIAccess was rejected"));
+        }
+        Self::new_with_options(client_name, options)
+    }
+
     pub fn port_count(&self) -> usize {
         STATIC.with(|s|{
             let mut s = s.borrow_mut();
@@ -252,6 +475,11 @@ impl MidiOutput {
         })
     }
 
+    pub fn set_port_watcher(&mut self, callback: Box<dyn FnMut(PortEvent) + Send>) -> PortWatcher {
+        let id = STATIC.with(|s| s.borrow_mut().add_output_watcher(callback));
+        PortWatcher { id, kind: PortWatcherKind::Output }
+    }
+
     pub fn port_name(&self, port_number: usize) -> Result<String, PortInfoError> {
         STATIC.with(|s|{
             let s = s.borrow_mut();
@@ -261,6 +489,22 @@ impl MidiOutput {
         })
     }
 
+    pub fn port_state(&self, port_number: usize) -> PortState {
+        STATIC.with(|s| {
+            let s = s.borrow_mut();
+            if port_number >= s.output_set.len() { return PortState::Disconnected; }
+            port_state(&s.output_set.list()[port_number])
+        })
+    }
+
+    pub fn port_connection_state(&self, port_number: usize) -> PortConnectionState {
+        STATIC.with(|s| {
+            let s = s.borrow_mut();
+            if port_number >= s.output_set.len() { return PortConnectionState::Closed; }
+            port_connection_state(&s.output_set.list()[port_number])
+        })
+    }
+
     pub fn connect(self, port_number: usize, _port_name: &str) -> Result<MidiOutputConnection, ConnectError<MidiOutput>> {
         STATIC.with(|s|{
             let s = s.borrow();
@@ -287,4 +531,10 @@ impl MidiOutputConnection {
     pub fn send(&mut self, message: &[u8]) -> Result<(), SendError> {
         self.output.send(unsafe { Uint8Array::view(message) }.as_ref()).map_err(|_| SendError::Other("JavaScript exception"))
     }
+
+    pub fn send_at(&mut self, message: &[u8], timestamp_us: u64) -> Result<(), SendError> {
+        let timestamp_ms = timestamp_us as f64 / 1000.0; // us -> ms, the inverse of the ms -> us conversion used for input timestamps
+        self.output.send_with_timestamp(unsafe { Uint8Array::view(message) }.as_ref(), timestamp_ms)
+            .map_err(|_| SendError::Other("JavaScript exception"))
+    }
 }
@@ -3,14 +3,45 @@ use std::ops::Deref;
 use ::errors::*;
 use ::backend::{
     MidiInputPort as MidiInputPortImpl,
-    MidiInput as MidiInputImpl, 
+    MidiInput as MidiInputImpl,
     MidiInputConnection as MidiInputConnectionImpl,
     MidiOutputPort as MidiOutputPortImpl,
     MidiOutput as MidiOutputImpl,
-    MidiOutputConnection as MidiOutputConnectionImpl
+    MidiOutputConnection as MidiOutputConnectionImpl,
+    PortWatcher as PortWatcherImpl
 };
 use ::Ignore;
 
+/// Options controlling how `MidiInput`/`MidiOutput` request access to the
+/// system's MIDI devices, passed to `new_with_options`.
+///
+/// Currently only meaningful on the Web MIDI backend, where each field maps
+/// directly onto a `MIDIOptions` dictionary member passed to
+/// `navigator.requestMIDIAccess`; native backends ignore
+/// `include_software_synths`, and honor `request_sysex: false` by
+/// filtering out sysex messages via `Ignore::Sysex` rather than by
+/// avoiding a permission prompt.
+#[derive(Clone, Copy, Debug)]
+pub struct MidiAccessOptions {
+    /// Whether to request permission to send and receive system exclusive
+    /// messages. Web browsers only prompt the user for MIDI access at all
+    /// when this is set, so privacy-sensitive apps that don't need sysex
+    /// can set this to `false` to avoid the prompt.
+    pub request_sysex: bool,
+    /// Whether to include software-synthesizer ports in addition to
+    /// hardware ports.
+    pub include_software_synths: bool,
+}
+
+impl Default for MidiAccessOptions {
+    /// Matches the behavior of `new`: request sysex permission, and don't
+    /// ask for software synths specifically (backends that distinguish
+    /// them still surface them if the OS does).
+    fn default() -> Self {
+        MidiAccessOptions { request_sysex: true, include_software_synths: false }
+    }
+}
+
 // TODO: documentation
 pub struct MidiInputPort {
     pub(crate) imp: MidiInputPortImpl
@@ -19,6 +50,56 @@ pub struct MidiInputPort {
 // TODO: documentation
 pub type MidiInputPorts = Vec<MidiInputPort>;
 
+/// Describes a MIDI port being added to or removed from the system, as
+/// reported to a callback registered via `MidiInput::set_port_watcher` or
+/// `MidiOutput::set_port_watcher`.
+#[derive(Clone, Debug)]
+pub struct PortEvent {
+    /// A backend-specific identifier for the affected port. Stable across
+    /// repeated plug/unplug cycles of the same physical device where the
+    /// backend supports it.
+    pub id: String,
+    /// The human-readable name of the affected port, if the backend could
+    /// determine one (e.g. a disconnected port may no longer have a name
+    /// available).
+    pub name: Option<String>,
+    /// Whether the port appeared or disappeared.
+    pub kind: PortEventKind,
+}
+
+/// Whether a `PortEvent` represents a port becoming available or a port
+/// disappearing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortEventKind {
+    Added,
+    Removed,
+}
+
+/// Whether a previously-enumerated port is still present in the system.
+/// Checking this before `connect` turns an opaque `ConnectError` into a
+/// cheap pre-check, e.g. after the device was unplugged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortState {
+    Connected,
+    Disconnected,
+}
+
+/// Whether a previously-enumerated port is currently open for use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortConnectionState {
+    Open,
+    Closed,
+    Pending,
+}
+
+/// A subscription created by `MidiInput::set_port_watcher` or
+/// `MidiOutput::set_port_watcher`. The callback stays registered for as
+/// long as this guard is kept alive; dropping it unregisters the callback.
+pub struct PortWatcher {
+    #[allow(dead_code)] // only kept alive for its `Drop` impl
+    imp: PortWatcherImpl
+}
+
 /// An instance of `MidiInput` is required for anything related to MIDI input.
 /// Create one with `MidiInput::new`.
 pub struct MidiInput {
@@ -31,7 +112,31 @@ impl MidiInput {
     pub fn new(client_name: &str) -> Result<Self, InitError> {
         MidiInputImpl::new(client_name).map(|imp| MidiInput { imp: imp })
     }
-    
+
+    /// Like `new`, but with explicit control over `MidiAccessOptions` such
+    /// as whether to request sysex permission. See `MidiAccessOptions` for
+    /// what each option does on each backend.
+    pub fn new_with_options(client_name: &str, options: MidiAccessOptions) -> Result<Self, InitError> {
+        MidiInputImpl::new_with_options(client_name, options).map(|imp| MidiInput { imp: imp })
+    }
+
+    /// Like `new`, but on backends that need to await a user permission
+    /// prompt (currently only the Web MIDI backend) resolves only once
+    /// that prompt has been settled, so the returned `MidiInput` already
+    /// sees every granted port instead of racing the prompt.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn new_async(client_name: &str) -> Result<Self, InitError> {
+        MidiInputImpl::new_async(client_name).await.map(|imp| MidiInput { imp: imp })
+    }
+
+    /// Combines `new_async` and `new_with_options`: awaits the permission
+    /// prompt like `new_async`, using the sysex/software-synth settings
+    /// from `options` like `new_with_options`.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn new_async_with_options(client_name: &str, options: MidiAccessOptions) -> Result<Self, InitError> {
+        MidiInputImpl::new_async_with_options(client_name, options).await.map(|imp| MidiInput { imp: imp })
+    }
+
     /// Set flags to decide what kind of messages should be ignored (i.e., filtered out)
     /// by this `MidiInput`. By default, no messages are ignored.
     pub fn ignore(&mut self, flags: Ignore) {
@@ -42,7 +147,20 @@ impl MidiInput {
     pub fn ports(&self) -> MidiInputPorts {
         self.imp.ports_internal()
     }
-    
+
+    /// Subscribe to hot-plug notifications for MIDI input ports. The
+    /// provided `callback` is invoked with a `PortEvent` whenever a port is
+    /// added to or removed from the system.
+    ///
+    /// The subscription stays active for as long as the returned
+    /// `PortWatcher` is kept alive; dropping it unregisters the callback.
+    ///
+    /// Not every backend can observe hot-plug events; on such backends the
+    /// callback is simply never invoked.
+    pub fn set_port_watcher(&mut self, callback: impl FnMut(PortEvent) + Send + 'static) -> PortWatcher {
+        PortWatcher { imp: self.imp.set_port_watcher(Box::new(callback)) }
+    }
+
     /// Get the number of available MIDI input ports that *midir* can connect to.
     pub fn port_count(&self) -> usize {
         self.imp.port_count()
@@ -52,7 +170,20 @@ impl MidiInput {
     pub fn port_name(&self, port: &MidiInputPort) -> Result<String, PortInfoError> {
         self.imp.port_name(&port.imp)
     }
-    
+
+    /// Check whether a previously-enumerated port is still present in the
+    /// system. Useful to avoid an opaque `ConnectError` after a device was
+    /// unplugged since it was last enumerated.
+    pub fn state(&self, port: &MidiInputPort) -> PortState {
+        self.imp.port_state(&port.imp)
+    }
+
+    /// Check whether a previously-enumerated port is currently open for
+    /// use, e.g. by another `MidiInputConnection` or another application.
+    pub fn connection_state(&self, port: &MidiInputPort) -> PortConnectionState {
+        self.imp.port_connection_state(&port.imp)
+    }
+
     /// Connect to a specified MIDI input port in order to receive messages.
     /// For each incoming MIDI message, the provided `callback` function will
     /// be called. The first parameter of the callback function is a timestamp
@@ -79,11 +210,22 @@ impl MidiInput {
             Err(imp) => {
                 let kind = imp.kind();
                 Err(ConnectError::new(kind, MidiInput { imp: imp.into_inner() }))
-            } 
+            }
         }
     }
 }
 
+#[cfg(feature = "ipmidi")]
+impl MidiInput {
+    /// Like `new`, but joins `endpoints` instead of the `ipmidi` backend's
+    /// default `225.0.0.37:21928..+16` multicast range; each endpoint
+    /// becomes one port. Only available when the `ipmidi` backend is
+    /// selected.
+    pub fn new_with_endpoints(client_name: &str, endpoints: Vec<::std::net::SocketAddr>) -> Result<Self, InitError> {
+        MidiInputImpl::new_with_endpoints(client_name, endpoints).map(|imp| MidiInput { imp: imp })
+    }
+}
+
 #[cfg(unix)]
 impl<T: Send> ::os::unix::VirtualInput<T> for MidiInput {
     fn create_virtual<F>(
@@ -146,11 +288,48 @@ impl MidiOutput {
         MidiOutputImpl::new(client_name).map(|imp| MidiOutput { imp: imp })
     }
 
+    /// Like `new`, but with explicit control over `MidiAccessOptions` such
+    /// as whether to request sysex permission. See `MidiAccessOptions` for
+    /// what each option does on each backend.
+    pub fn new_with_options(client_name: &str, options: MidiAccessOptions) -> Result<Self, InitError> {
+        MidiOutputImpl::new_with_options(client_name, options).map(|imp| MidiOutput { imp: imp })
+    }
+
+    /// Like `new`, but on backends that need to await a user permission
+    /// prompt (currently only the Web MIDI backend) resolves only once
+    /// that prompt has been settled, so the returned `MidiOutput` already
+    /// sees every granted port instead of racing the prompt.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn new_async(client_name: &str) -> Result<Self, InitError> {
+        MidiOutputImpl::new_async(client_name).await.map(|imp| MidiOutput { imp: imp })
+    }
+
+    /// Combines `new_async` and `new_with_options`: awaits the permission
+    /// prompt like `new_async`, using the sysex/software-synth settings
+    /// from `options` like `new_with_options`.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn new_async_with_options(client_name: &str, options: MidiAccessOptions) -> Result<Self, InitError> {
+        MidiOutputImpl::new_async_with_options(client_name, options).await.map(|imp| MidiOutput { imp: imp })
+    }
+
     // TODO: documentation
     pub fn ports(&self) -> MidiOutputPorts {
         MidiOutputPorts { inner: self.imp.ports_internal() }
     }
-    
+
+    /// Subscribe to hot-plug notifications for MIDI output ports. The
+    /// provided `callback` is invoked with a `PortEvent` whenever a port is
+    /// added to or removed from the system.
+    ///
+    /// The subscription stays active for as long as the returned
+    /// `PortWatcher` is kept alive; dropping it unregisters the callback.
+    ///
+    /// Not every backend can observe hot-plug events; on such backends the
+    /// callback is simply never invoked.
+    pub fn set_port_watcher(&mut self, callback: impl FnMut(PortEvent) + Send + 'static) -> PortWatcher {
+        PortWatcher { imp: self.imp.set_port_watcher(Box::new(callback)) }
+    }
+
     /// Get the number of available MIDI output ports that *midir* can connect to.
     pub fn port_count(&self) -> usize {
         self.imp.port_count()
@@ -160,7 +339,20 @@ impl MidiOutput {
     pub fn port_name(&self, port: &MidiOutputPort) -> Result<String, PortInfoError> {
         self.imp.port_name(&port.imp)
     }
-    
+
+    /// Check whether a previously-enumerated port is still present in the
+    /// system. Useful to avoid an opaque `ConnectError` after a device was
+    /// unplugged since it was last enumerated.
+    pub fn state(&self, port: &MidiOutputPort) -> PortState {
+        self.imp.port_state(&port.imp)
+    }
+
+    /// Check whether a previously-enumerated port is currently open for
+    /// use, e.g. by another `MidiOutputConnection` or another application.
+    pub fn connection_state(&self, port: &MidiOutputPort) -> PortConnectionState {
+        self.imp.port_connection_state(&port.imp)
+    }
+
     /// Connect to a specified MIDI output port in order to send messages.
     /// The connection will be kept open as long as the returned
     /// `MidiOutputConnection` is kept alive.
@@ -173,11 +365,22 @@ impl MidiOutput {
             Err(imp) => {
                 let kind = imp.kind();
                 Err(ConnectError::new(kind, MidiOutput { imp: imp.into_inner() }))
-            } 
+            }
         }
     }
 }
 
+#[cfg(feature = "ipmidi")]
+impl MidiOutput {
+    /// Like `new`, but joins `endpoints` instead of the `ipmidi` backend's
+    /// default `225.0.0.37:21928..+16` multicast range; each endpoint
+    /// becomes one port. Only available when the `ipmidi` backend is
+    /// selected.
+    pub fn new_with_endpoints(client_name: &str, endpoints: Vec<::std::net::SocketAddr>) -> Result<Self, InitError> {
+        MidiOutputImpl::new_with_endpoints(client_name, endpoints).map(|imp| MidiOutput { imp: imp })
+    }
+}
+
 #[cfg(unix)]
 impl ::os::unix::VirtualOutput for MidiOutput {
     fn create_virtual(self, port_name: &str) -> Result<MidiOutputConnection, ConnectError<MidiOutput>> {
@@ -208,6 +411,19 @@ impl MidiOutputConnection {
     pub fn send(&mut self, message: &[u8]) -> Result<(), SendError> {
         self.imp.send(message)
     }
+
+    /// Schedule a message to be sent at `timestamp_us`, a point in time in
+    /// the same microsecond epoch as the timestamps passed to
+    /// `MidiInputConnection` callbacks. The message must be a valid MIDI
+    /// message, as with `send`.
+    ///
+    /// On backends with no hardware scheduling of their own, this is
+    /// implemented with a software timer; delivery precision is therefore
+    /// bounded by OS scheduling jitter. `send` is equivalent to calling
+    /// `send_at` with a timestamp of "now".
+    pub fn send_at(&mut self, message: &[u8], timestamp_us: u64) -> Result<(), SendError> {
+        self.imp.send_at(message, timestamp_us)
+    }
 }
 
 #[cfg(test)]
@@ -224,5 +440,6 @@ mod tests {
         is_send::<MidiOutputPort>();
         is_send::<MidiOutput>();
         is_send::<MidiOutputConnection>();
+        is_send::<PortWatcher>();
     }
 }